@@ -1,16 +1,15 @@
-use std::collections::{HashSet, BTreeMap};
-use std::convert::TryInto;
+use std::collections::{HashSet, BTreeMap, BTreeSet};
+use std::path::Path;
 use std::sync::Arc;
-use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use arc_swap::{ArcSwap, ArcSwapOption, Guard};
+use chrono::{DateTime, Utc};
 use meilidb_core::criterion::Criteria;
 use meilidb_core::{DocIndex, Store, DocumentId, QueryBuilder};
 use meilidb_schema::Schema;
 use sdset::SetBuf;
 use serde::{de, Serialize, Deserialize};
-use sled::Transactional;
 
 use crate::ranked_map::RankedMap;
 use crate::serde::{Deserializer, DeserializerError};
@@ -21,51 +20,67 @@ use self::documents_index::DocumentsIndex;
 use self::main_index::MainIndex;
 use self::synonyms_index::SynonymsIndex;
 use self::words_index::WordsIndex;
+pub use self::state_lock::{StateLock, UpdateState};
+pub use self::store::{KvBatch, KvStore, KvTree, KvSubscription};
+pub use self::update_store::UpdateStore;
 
 use crate::database::{
     Error,
-    DocumentsAddition, DocumentsDeletion,
+    DocumentsAddition, DocumentsPartialAddition, DocumentsDeletion,
     SynonymsAddition, SynonymsDeletion,
-    apply_documents_addition, apply_documents_deletion,
-    apply_synonyms_addition, apply_synonyms_deletion,
+    StopWordsAddition, StopWordsDeletion,
 };
 
 mod custom_settings_index;
 mod docs_words_index;
 mod documents_index;
 mod main_index;
+mod state_lock;
+mod store;
 mod synonyms_index;
+mod update_store;
 mod words_index;
 
-fn event_is_set(event: &sled::Event) -> bool {
-    match event {
-        sled::Event::Set(_, _) => true,
-        _ => false,
-    }
-}
-
 #[derive(Deserialize)]
 enum UpdateOwned {
     DocumentsAddition(Vec<rmpv::Value>),
+    DocumentsPartialAddition(Vec<rmpv::Value>),
     DocumentsDeletion(Vec<DocumentId>),
     SynonymsAddition(BTreeMap<String, Vec<String>>),
     SynonymsDeletion(BTreeMap<String, Option<Vec<String>>>),
+    ClearAll,
+    SchemaUpdate(Schema),
+    StopWordsAddition(BTreeSet<String>),
+    StopWordsDeletion(BTreeSet<String>),
+    CustomsUpdate(Vec<u8>),
 }
 
 #[derive(Serialize)]
 enum Update {
     DocumentsAddition(Vec<rmpv::Value>),
+    DocumentsPartialAddition(Vec<rmpv::Value>),
     DocumentsDeletion(Vec<DocumentId>),
     SynonymsAddition(BTreeMap<String, Vec<String>>),
     SynonymsDeletion(BTreeMap<String, Option<Vec<String>>>),
+    ClearAll,
+    SchemaUpdate(Schema),
+    StopWordsAddition(BTreeSet<String>),
+    StopWordsDeletion(BTreeSet<String>),
+    CustomsUpdate(Vec<u8>),
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub enum UpdateType {
     DocumentsAddition { number: usize },
+    DocumentsPartialAddition { number: usize },
     DocumentsDeletion { number: usize },
     SynonymsAddition { number: usize },
     SynonymsDeletion { number: usize },
+    ClearAll,
+    SchemaUpdate,
+    StopWordsAddition { number: usize },
+    StopWordsDeletion { number: usize },
+    CustomsUpdate,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -73,80 +88,28 @@ pub struct DetailedDuration {
     main: Duration,
 }
 
+/// The outcome of an update that has finished being applied, along with the
+/// timestamps needed to compute queue latency (`processed_at - enqueued_at`)
+/// versus actual processing time (`detailed_duration.main`).
 #[derive(Clone, Serialize, Deserialize)]
-pub struct UpdateStatus {
+pub struct UpdateResult {
     pub update_id: u64,
     pub update_type: UpdateType,
     pub result: Result<(), String>,
     pub detailed_duration: DetailedDuration,
+    pub enqueued_at: DateTime<Utc>,
+    pub processed_at: DateTime<Utc>,
 }
 
-fn spawn_update_system(index: Index) -> thread::JoinHandle<()> {
-    thread::spawn(move || {
-        loop {
-            let subscription = index.updates_index.watch_prefix(vec![]);
-            while let Some(result) = index.updates_index.iter().next() {
-                let (key, _) = result.unwrap();
-                let update_id = key.as_ref().try_into().map(u64::from_be_bytes).unwrap();
-
-                let updates = &index.updates_index;
-                let results = &index.updates_results_index;
-
-                (updates, results).transaction(|(updates, results)| {
-                    let update = updates.remove(&key)?.unwrap();
-
-                    let (update_type, result, duration) = match rmp_serde::from_read_ref(&update).unwrap() {
-                        UpdateOwned::DocumentsAddition(documents) => {
-                            let update_type = UpdateType::DocumentsAddition { number: documents.len() };
-                            let ranked_map = index.cache.load().ranked_map.clone();
-                            let start = Instant::now();
-                            let result = apply_documents_addition(&index, ranked_map, documents);
-                            (update_type, result, start.elapsed())
-                        },
-                        UpdateOwned::DocumentsDeletion(documents) => {
-                            let update_type = UpdateType::DocumentsDeletion { number: documents.len() };
-                            let ranked_map = index.cache.load().ranked_map.clone();
-                            let start = Instant::now();
-                            let result = apply_documents_deletion(&index, ranked_map, documents);
-                            (update_type, result, start.elapsed())
-                        },
-                        UpdateOwned::SynonymsAddition(synonyms) => {
-                            let update_type = UpdateType::SynonymsAddition { number: synonyms.len() };
-                            let start = Instant::now();
-                            let result = apply_synonyms_addition(&index, synonyms);
-                            (update_type, result, start.elapsed())
-                        },
-                        UpdateOwned::SynonymsDeletion(synonyms) => {
-                            let update_type = UpdateType::SynonymsDeletion { number: synonyms.len() };
-                            let start = Instant::now();
-                            let result = apply_synonyms_deletion(&index, synonyms);
-                            (update_type, result, start.elapsed())
-                        },
-                    };
-
-                    let detailed_duration = DetailedDuration { main: duration };
-                    let status = UpdateStatus {
-                        update_id,
-                        update_type,
-                        result: result.map_err(|e| e.to_string()),
-                        detailed_duration,
-                    };
-
-                    if let Some(callback) = &*index.update_callback.load() {
-                        (callback)(status.clone());
-                    }
-
-                    let value = bincode::serialize(&status).unwrap();
-                    results.insert(&key, value)
-                })
-                .unwrap();
-            }
-
-            // this subscription is just used to block
-            // the loop until a new update is inserted
-            subscription.filter(event_is_set).next();
-        }
-    })
+/// Where an update stands in the queue: still waiting (`Enqueued`), being
+/// applied right now by the worker (`Processing`), done (`Processed`), or
+/// simply never heard of (`Unknown`, e.g. a stale or made-up `update_id`).
+#[derive(Clone, Serialize, Deserialize)]
+pub enum UpdateStatus {
+    Enqueued { update_id: u64, update_type: UpdateType, enqueued_at: DateTime<Utc> },
+    Processing { update_id: u64, update_type: UpdateType, enqueued_at: DateTime<Utc> },
+    Processed(UpdateResult),
+    Unknown,
 }
 
 #[derive(Copy, Clone)]
@@ -154,11 +117,13 @@ pub struct IndexStats {
     pub number_of_words: usize,
     pub number_of_documents: usize,
     pub number_attrs_in_ranked_map: usize,
+    pub update_state: UpdateState,
 }
 
 #[derive(Clone)]
 pub struct Index {
     pub(crate) cache: Arc<ArcSwap<Cache>>,
+    pub(crate) name: String,
 
     // TODO this will be a snapshot in the future
     main_index: MainIndex,
@@ -168,11 +133,14 @@ pub struct Index {
     documents_index: DocumentsIndex,
     custom_settings_index: CustomSettingsIndex,
 
-    // used by the update system
+    // kept around for `snapshot`, which needs to open and copy this
+    // index's own trees independently of the shared update queue
     db: sled::Db,
-    updates_index: Arc<sled::Tree>,
-    updates_results_index: Arc<sled::Tree>,
-    update_callback: Arc<ArcSwapOption<Box<dyn Fn(UpdateStatus) + Send + Sync + 'static>>>,
+
+    // the update queue is shared and ordered across every index opened
+    // against the same store, see `UpdateStore`
+    pub(crate) update_store: UpdateStore,
+    update_callback: Arc<ArcSwapOption<Box<dyn Fn(UpdateResult) + Send + Sync + 'static>>>,
 }
 
 pub(crate) struct Cache {
@@ -183,23 +151,33 @@ pub(crate) struct Cache {
 }
 
 impl Index {
-    pub fn new(db: sled::Db, name: &str) -> Result<Index, Error> {
-        Index::new_raw(db, name, None)
+    pub fn new(db: sled::Db, update_store: &UpdateStore, name: &str) -> Result<Index, Error> {
+        Index::new_raw(db, update_store, name, None)
     }
 
-    pub fn with_schema(db: sled::Db, name: &str, schema: Schema) -> Result<Index, Error> {
-        Index::new_raw(db, name, Some(schema))
+    pub fn with_schema(
+        db: sled::Db,
+        update_store: &UpdateStore,
+        name: &str,
+        schema: Schema,
+    ) -> Result<Index, Error>
+    {
+        Index::new_raw(db, update_store, name, Some(schema))
     }
 
-    fn new_raw(db: sled::Db, name: &str, schema: Option<Schema>) -> Result<Index, Error> {
+    fn new_raw(
+        db: sled::Db,
+        update_store: &UpdateStore,
+        name: &str,
+        schema: Option<Schema>,
+    ) -> Result<Index, Error>
+    {
         let main_index = db.open_tree(name).map(MainIndex)?;
-        let synonyms_index = db.open_tree(format!("{}-synonyms", name)).map(SynonymsIndex)?;
-        let words_index = db.open_tree(format!("{}-words", name)).map(WordsIndex)?;
-        let docs_words_index = db.open_tree(format!("{}-docs-words", name)).map(DocsWordsIndex)?;
-        let documents_index = db.open_tree(format!("{}-documents", name)).map(DocumentsIndex)?;
-        let custom_settings_index = db.open_tree(format!("{}-custom", name)).map(CustomSettingsIndex)?;
-        let updates_index = db.open_tree(format!("{}-updates", name))?;
-        let updates_results_index = db.open_tree(format!("{}-updates-results", name))?;
+        let synonyms_index = db.open_tree(&format!("{}-synonyms", name)).map(SynonymsIndex)?;
+        let words_index = db.open_tree(&format!("{}-words", name)).map(WordsIndex)?;
+        let docs_words_index = db.open_tree(&format!("{}-docs-words", name)).map(DocsWordsIndex)?;
+        let documents_index = db.open_tree(&format!("{}-documents", name)).map(DocumentsIndex)?;
+        let custom_settings_index = db.open_tree(&format!("{}-custom", name)).map(CustomSettingsIndex)?;
 
         let words = match main_index.words_set()? {
             Some(words) => Arc::new(words),
@@ -211,9 +189,18 @@ impl Index {
             None => Arc::new(fst::Set::default()),
         };
 
+        // an expected schema that differs from the one already on disk no
+        // longer hard-fails the open: `schema_update` is queued below once
+        // the index is constructed, going through `apply_schema_update`'s
+        // own validation (reindexing changed searchable/ranked attributes,
+        // rejecting an incompatible identifier change) instead of this
+        // duplicating that check
+        let mut pending_schema_update = None;
+
         let schema = match (schema, main_index.schema()?) {
-            (Some(ref expected), Some(ref current)) if current != expected => {
-                return Err(Error::SchemaDiffer)
+            (Some(expected), Some(current)) if current != expected => {
+                pending_schema_update = Some(expected);
+                current
             },
             (Some(expected), Some(_)) => expected,
             (Some(expected), None) => {
@@ -234,6 +221,7 @@ impl Index {
 
         let index = Index {
             cache,
+            name: name.to_string(),
             main_index,
             synonyms_index,
             words_index,
@@ -241,18 +229,21 @@ impl Index {
             documents_index,
             custom_settings_index,
             db,
-            updates_index,
-            updates_results_index,
+            update_store: update_store.clone(),
             update_callback: Arc::new(ArcSwapOption::empty()),
         };
 
-        let _handle = spawn_update_system(index.clone());
+        update_store.register(name.to_string(), index.clone());
+
+        if let Some(expected) = pending_schema_update {
+            index.schema_update(expected)?;
+        }
 
         Ok(index)
     }
 
     pub fn set_update_callback<F>(&self, callback: F)
-    where F: Fn(UpdateStatus) + Send + Sync + 'static
+    where F: Fn(UpdateResult) + Send + Sync + 'static
     {
         self.update_callback.store(Some(Arc::new(Box::new(callback))));
     }
@@ -267,9 +258,48 @@ impl Index {
             number_of_words: cache.words.len(),
             number_of_documents: self.documents_index.len()?,
             number_attrs_in_ranked_map: cache.ranked_map.len(),
+            update_state: self.update_store.state_lock().current(),
         })
     }
 
+    /// Copies this index's trees into a fresh store at `path`, along with
+    /// this index's own slice of the shared update queue (its pending
+    /// updates and recorded results), so the snapshot is restorable on its
+    /// own without depending on the rest of the store's indexes.
+    ///
+    /// Takes the update worker's exclusive lock first, so no update begins
+    /// applying mid-copy.
+    pub fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let _guard = self.update_store.state_lock().snapshotting();
+
+        self.db.flush()?;
+
+        let snapshot_db = sled::Db::open(path)?;
+
+        let tree_names = [
+            self.name.clone(),
+            format!("{}-synonyms", self.name),
+            format!("{}-words", self.name),
+            format!("{}-docs-words", self.name),
+            format!("{}-documents", self.name),
+            format!("{}-custom", self.name),
+        ];
+
+        for tree_name in &tree_names {
+            let source = self.db.open_tree(tree_name)?;
+            let target = snapshot_db.open_tree(tree_name)?;
+
+            for entry in source.iter() {
+                let (key, value) = entry?;
+                target.insert(key, value)?;
+            }
+        }
+
+        self.update_store.snapshot_index(&self.name, &snapshot_db)?;
+
+        Ok(())
+    }
+
     pub fn query_builder(&self) -> QueryBuilder<RefIndex> {
         let ref_index = self.as_ref();
         QueryBuilder::new(ref_index)
@@ -308,6 +338,10 @@ impl Index {
         DocumentsAddition::new(self)
     }
 
+    pub fn documents_partial_addition<D>(&self) -> DocumentsPartialAddition<D> {
+        DocumentsPartialAddition::new(self)
+    }
+
     pub fn documents_deletion(&self) -> DocumentsDeletion {
         DocumentsDeletion::new(self)
     }
@@ -320,41 +354,46 @@ impl Index {
         SynonymsDeletion::new(self)
     }
 
+    pub fn stop_words_addition(&self) -> StopWordsAddition {
+        StopWordsAddition::new(self)
+    }
+
+    pub fn stop_words_deletion(&self) -> StopWordsDeletion {
+        StopWordsDeletion::new(self)
+    }
+
+    pub fn clear_all(&self) -> Result<u64, Error> {
+        let update = rmp_serde::to_vec_named(&Update::ClearAll)?;
+        self.raw_push_update(update)
+    }
+
+    pub fn schema_update(&self, schema: Schema) -> Result<u64, Error> {
+        let update = Update::SchemaUpdate(schema);
+        let update = rmp_serde::to_vec_named(&update)?;
+        self.raw_push_update(update)
+    }
+
+    pub fn customs_update(&self, customs: Vec<u8>) -> Result<u64, Error> {
+        let update = Update::CustomsUpdate(customs);
+        let update = rmp_serde::to_vec_named(&update)?;
+        self.raw_push_update(update)
+    }
+
     pub fn update_status(
         &self,
         update_id: u64,
-    ) -> Result<Option<UpdateStatus>, Error>
+    ) -> Result<UpdateStatus, Error>
     {
-        let update_id = update_id.to_be_bytes();
-        match self.updates_results_index.get(update_id)? {
-            Some(value) => {
-                let value = bincode::deserialize(&value)?;
-                Ok(Some(value))
-            },
-            None => Ok(None),
-        }
+        self.update_store.status(&self.name, update_id)
     }
 
+    /// Blocks until `update_id` has been processed, then returns its result.
     pub fn update_status_blocking(
         &self,
         update_id: u64,
-    ) -> Result<UpdateStatus, Error>
+    ) -> Result<UpdateResult, Error>
     {
-        let update_id_bytes = update_id.to_be_bytes().to_vec();
-        let mut subscription = self.updates_results_index.watch_prefix(update_id_bytes);
-
-        // if we find the update result return it now
-        if let Some(result) = self.update_status(update_id)? {
-            return Ok(result)
-        }
-
-        // this subscription is used to block the thread
-        // until the update_id is inserted in the tree
-        subscription.next();
-
-        // the thread has been unblocked, it means that the update result
-        // has been inserted in the tree, retrieve it
-        Ok(self.update_status(update_id)?.unwrap())
+        self.update_store.wait_result(&self.name, update_id)
     }
 
     pub fn document<T>(
@@ -398,6 +437,21 @@ impl Index {
         self.raw_push_update(update)
     }
 
+    pub(crate) fn push_documents_partial_addition<D>(&self, addition: Vec<D>) -> Result<u64, Error>
+    where D: serde::Serialize
+    {
+        let mut values = Vec::with_capacity(addition.len());
+        for add in addition {
+            let vec = rmp_serde::to_vec_named(&add)?;
+            let add = rmp_serde::from_read(&vec[..])?;
+            values.push(add);
+        }
+
+        let addition = Update::DocumentsPartialAddition(values);
+        let update = rmp_serde::to_vec_named(&addition)?;
+        self.raw_push_update(update)
+    }
+
     pub(crate) fn push_documents_deletion(
         &self,
         deletion: Vec<DocumentId>,
@@ -428,13 +482,28 @@ impl Index {
         self.raw_push_update(update)
     }
 
-    fn raw_push_update(&self, raw_update: Vec<u8>) -> Result<u64, Error> {
-        let update_id = self.db.generate_id()?;
-        let update_id_array = update_id.to_be_bytes();
+    pub(crate) fn push_stop_words_addition(
+        &self,
+        addition: BTreeSet<String>,
+    ) -> Result<u64, Error>
+    {
+        let addition = Update::StopWordsAddition(addition);
+        let update = rmp_serde::to_vec_named(&addition)?;
+        self.raw_push_update(update)
+    }
 
-        self.updates_index.insert(update_id_array, raw_update)?;
+    pub(crate) fn push_stop_words_deletion(
+        &self,
+        deletion: BTreeSet<String>,
+    ) -> Result<u64, Error>
+    {
+        let deletion = Update::StopWordsDeletion(deletion);
+        let update = rmp_serde::to_vec_named(&deletion)?;
+        self.raw_push_update(update)
+    }
 
-        Ok(update_id)
+    fn raw_push_update(&self, raw_update: Vec<u8>) -> Result<u64, Error> {
+        self.update_store.enqueue(&self.name, raw_update)
     }
 }
 
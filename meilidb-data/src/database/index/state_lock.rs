@@ -0,0 +1,80 @@
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// What the update worker is doing right now.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UpdateState {
+    Idle,
+    Processing(u64),
+    Snapshotting,
+}
+
+/// Gates access between the update worker and anything that needs a
+/// consistent view of the backing `sled::Db` — today, only `Index::snapshot`.
+///
+/// Readers (searches, `update_status`) never block each other, they just
+/// read whatever `current()` returns. The worker and `snapshot()` each take
+/// the *write* side before they start mutating/copying trees, so at most one
+/// of "apply an update" and "take a snapshot" happens at a time, and a
+/// snapshot never starts mid-update.
+pub struct StateLock {
+    rwlock: RwLock<()>,
+    state: RwLock<UpdateState>,
+}
+
+/// Held for the duration of an exclusive operation; resets the reported
+/// state back to `Idle` when dropped.
+pub struct StateLockGuard<'a> {
+    _rwlock_guard: RwLockWriteGuard<'a, ()>,
+    state: &'a RwLock<UpdateState>,
+}
+
+impl StateLock {
+    pub fn new() -> StateLock {
+        StateLock {
+            rwlock: RwLock::new(()),
+            state: RwLock::new(UpdateState::Idle),
+        }
+    }
+
+    /// The state as of right now. May be stale the instant it's read, same
+    /// as any other lock-free snapshot of shared state.
+    pub fn current(&self) -> UpdateState {
+        *self.state.read().unwrap()
+    }
+
+    /// Takes a non-exclusive read lock, letting callers assert "no
+    /// exclusive operation is running" without caring which one.
+    pub fn read(&self) -> RwLockReadGuard<()> {
+        self.rwlock.read().unwrap()
+    }
+
+    /// Takes the exclusive lock and reports `Processing(update_id)` for as
+    /// long as the returned guard is alive.
+    pub fn processing(&self, update_id: u64) -> StateLockGuard {
+        self.acquire(UpdateState::Processing(update_id))
+    }
+
+    /// Takes the exclusive lock and reports `Snapshotting` for as long as
+    /// the returned guard is alive.
+    pub fn snapshotting(&self) -> StateLockGuard {
+        self.acquire(UpdateState::Snapshotting)
+    }
+
+    fn acquire(&self, state: UpdateState) -> StateLockGuard {
+        let rwlock_guard = self.rwlock.write().unwrap();
+        *self.state.write().unwrap() = state;
+        StateLockGuard { _rwlock_guard: rwlock_guard, state: &self.state }
+    }
+}
+
+impl Drop for StateLockGuard<'_> {
+    fn drop(&mut self) {
+        *self.state.write().unwrap() = UpdateState::Idle;
+    }
+}
+
+impl Default for StateLock {
+    fn default() -> StateLock {
+        StateLock::new()
+    }
+}
@@ -0,0 +1,163 @@
+use crossbeam_channel::Receiver;
+
+/// Abstraction over the key/value engine backing [`UpdateStore`](super::UpdateStore)'s
+/// shared update queue.
+///
+/// `sled` is the only implementor today, but nothing here is specific to it:
+/// `transaction` hands the closure a pair of [`KvBatch`]s rather than sled's
+/// own `TransactionalTree`, and its own error type never leaks sled's
+/// transaction machinery. `Index` itself isn't generic over this trait —
+/// its six per-index trees are defined in their own modules directly
+/// against `sled::Tree` — so moving it to another backend would still mean
+/// reworking those, not just this trait.
+pub trait KvStore: Clone + Send + Sync + 'static {
+    type Tree: KvTree<Error = Self::Error>;
+    type Batch: KvBatch<Error = Self::BatchError>;
+    type Error: std::error::Error + Send + Sync + 'static;
+    type BatchError: std::error::Error + Send + Sync + 'static + Into<Self::Error>;
+
+    /// Opens (creating if necessary) the named tree/column family.
+    fn open_tree(&self, name: &str) -> Result<Self::Tree, Self::Error>;
+
+    /// Returns a new, monotonically increasing identifier, unique across
+    /// every tree opened from this store.
+    fn generate_id(&self) -> Result<u64, Self::Error>;
+
+    /// Atomically applies `f` across two trees opened from this store,
+    /// rolling both of them back if `f` returns an error.
+    fn transaction<T>(
+        &self,
+        a: &Self::Tree,
+        b: &Self::Tree,
+        f: impl Fn(&Self::Batch, &Self::Batch) -> Result<T, Self::BatchError>,
+    ) -> Result<T, Self::Error>;
+}
+
+/// A single ordered key/value tree inside a [`KvStore`].
+pub trait KvTree: Clone + Send + Sync + 'static {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), Self::Error>> + 'a>;
+
+    /// Subscribes to every write whose key starts with `prefix`.
+    fn watch_prefix(&self, prefix: &[u8]) -> KvSubscription;
+}
+
+/// The same three operations as [`KvTree`], scoped to a single
+/// [`KvStore::transaction`] call.
+///
+/// Kept as its own trait, rather than reusing `KvTree`, because backends
+/// commonly give in-transaction operations their own error type, distinct
+/// from the store's own — `sled::transaction::UnabortableTransactionError`,
+/// say, as opposed to `sled::Error`.
+pub trait KvBatch {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+}
+
+/// A one-shot wakeup fired the next time a watched tree is written to.
+///
+/// `sled` exposes this natively through `Tree::watch_prefix`. A backend
+/// without prefix-watch support (RocksDB, say) can implement it by handing
+/// out the receiving end of a `crossbeam_channel` and broadcasting on that
+/// channel's sender after every write that matches the prefix.
+pub enum KvSubscription {
+    Sled(sled::Subscriber),
+    Channel(Receiver<()>),
+}
+
+impl KvSubscription {
+    /// Blocks until the next matching write, then returns.
+    pub fn wait(self) {
+        match self {
+            KvSubscription::Sled(subscriber) => {
+                subscriber.filter(event_is_set).next();
+            }
+            KvSubscription::Channel(receiver) => {
+                let _ = receiver.recv();
+            }
+        }
+    }
+}
+
+fn event_is_set(event: &sled::Event) -> bool {
+    match event {
+        sled::Event::Set(_, _) => true,
+        _ => false,
+    }
+}
+
+impl KvTree for sled::Tree {
+    type Error = sled::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(sled::Tree::get(self, key)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(sled::Tree::insert(self, key, value)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(sled::Tree::remove(self, key)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), Self::Error>> + 'a> {
+        Box::new(sled::Tree::iter(self).map(|res| res.map(|(k, v)| (k.to_vec(), v.to_vec()))))
+    }
+
+    fn watch_prefix(&self, prefix: &[u8]) -> KvSubscription {
+        KvSubscription::Sled(sled::Tree::watch_prefix(self, prefix))
+    }
+}
+
+impl KvBatch for sled::transaction::TransactionalTree {
+    type Error = sled::transaction::UnabortableTransactionError;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(sled::transaction::TransactionalTree::get(self, key)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(sled::transaction::TransactionalTree::insert(self, key, value)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(sled::transaction::TransactionalTree::remove(self, key)?.map(|ivec| ivec.to_vec()))
+    }
+}
+
+impl KvStore for sled::Db {
+    type Tree = sled::Tree;
+    type Batch = sled::transaction::TransactionalTree;
+    type Error = sled::Error;
+    type BatchError = sled::transaction::UnabortableTransactionError;
+
+    fn open_tree(&self, name: &str) -> Result<Self::Tree, Self::Error> {
+        sled::Db::open_tree(self, name)
+    }
+
+    fn generate_id(&self) -> Result<u64, Self::Error> {
+        sled::Db::generate_id(self)
+    }
+
+    fn transaction<T>(
+        &self,
+        a: &Self::Tree,
+        b: &Self::Tree,
+        f: impl Fn(&Self::Batch, &Self::Batch) -> Result<T, Self::BatchError>,
+    ) -> Result<T, Self::Error> {
+        use sled::Transactional;
+        (a, b).transaction(|(a, b)| f(a, b).map_err(sled::transaction::ConflictableTransactionError::Abort))
+            .map_err(|e| match e {
+                sled::transaction::TransactionError::Abort(e) => e.into(),
+                sled::transaction::TransactionError::Storage(e) => e,
+            })
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Instant;
+
+use chrono::Utc;
+use serde::{Serialize, Deserialize};
+
+use crate::database::{
+    Error,
+    apply_documents_addition, apply_documents_partial_addition, apply_documents_deletion,
+    apply_synonyms_addition, apply_synonyms_deletion,
+    apply_clear_all, apply_schema_update,
+    apply_stop_words_addition, apply_stop_words_deletion,
+    apply_customs_update,
+};
+
+use super::{DetailedDuration, Index, KvBatch, KvStore, KvTree, StateLock, UpdateOwned, UpdateResult, UpdateState, UpdateStatus, UpdateType};
+
+/// What actually gets stored in the `pending_queue` tree: the raw,
+/// `rmp_serde`-encoded `Update` alongside the moment it was enqueued, so
+/// that moment survives into the `UpdateResult` once the update is applied.
+///
+/// `local_id` is this update's per-index, caller-facing id (see
+/// `next_update_id`'s doc comment) — stored here so the worker can read it
+/// straight off the queue entry instead of translating `global_id` back to
+/// a `local_id` itself.
+#[derive(Serialize, Deserialize)]
+struct PendingUpdate {
+    local_id: u64,
+    enqueued_at: chrono::DateTime<Utc>,
+    update: Vec<u8>,
+}
+
+/// Mirrors `PendingUpdate` but borrows its payload instead of owning it, so
+/// `enqueue` can serialize one from inside its transaction closure — which
+/// sled may re-run on conflict — without recloning `raw_update` on every
+/// attempt.
+#[derive(Serialize)]
+struct PendingUpdateRef<'a> {
+    local_id: u64,
+    enqueued_at: chrono::DateTime<Utc>,
+    update: &'a [u8],
+}
+
+/// Turns a decoded update into the lightweight `UpdateType` used to report
+/// status on updates that haven't been applied yet.
+fn describe_update(update: &UpdateOwned) -> UpdateType {
+    match update {
+        UpdateOwned::DocumentsAddition(docs) => UpdateType::DocumentsAddition { number: docs.len() },
+        UpdateOwned::DocumentsPartialAddition(docs) => UpdateType::DocumentsPartialAddition { number: docs.len() },
+        UpdateOwned::DocumentsDeletion(docs) => UpdateType::DocumentsDeletion { number: docs.len() },
+        UpdateOwned::SynonymsAddition(syn) => UpdateType::SynonymsAddition { number: syn.len() },
+        UpdateOwned::SynonymsDeletion(syn) => UpdateType::SynonymsDeletion { number: syn.len() },
+        UpdateOwned::ClearAll => UpdateType::ClearAll,
+        UpdateOwned::SchemaUpdate(_) => UpdateType::SchemaUpdate,
+        UpdateOwned::StopWordsAddition(words) => UpdateType::StopWordsAddition { number: words.len() },
+        UpdateOwned::StopWordsDeletion(words) => UpdateType::StopWordsDeletion { number: words.len() },
+        UpdateOwned::CustomsUpdate(_) => UpdateType::CustomsUpdate,
+    }
+}
+
+/// Builds the key every update is stored under, both in the `pending_queue`
+/// and in `updates_results`: the global, strictly increasing id first so
+/// trees stay ordered by enqueue order, the index name appended so the
+/// worker (and `UpdateStore::results_for_index`) can tell updates of
+/// different indexes apart.
+fn update_key(global_id: u64, index_name: &str) -> Vec<u8> {
+    let mut key = global_id.to_be_bytes().to_vec();
+    key.extend_from_slice(index_name.as_bytes());
+    key
+}
+
+fn decode_update_key(key: &[u8]) -> (u64, &str) {
+    let (id_bytes, name_bytes) = key.split_at(8);
+    let global_id = u64::from_be_bytes(id_bytes.try_into().unwrap());
+    let index_name = std::str::from_utf8(name_bytes).unwrap();
+    (global_id, index_name)
+}
+
+/// Key `next_update_id` stores each index's own last assigned `local_id` under.
+fn counter_key(index_name: &str) -> Vec<u8> {
+    let mut key = b"counter-".to_vec();
+    key.extend_from_slice(index_name.as_bytes());
+    key
+}
+
+/// Key `next_update_id` stores the `local_id -> global_id` mapping under,
+/// so a caller's `update_id` (a `local_id`) can be translated back into the
+/// key `pending_queue`/`updates_results` actually index by.
+fn local_id_key(index_name: &str, local_id: u64) -> Vec<u8> {
+    let mut key = b"local-".to_vec();
+    key.extend_from_slice(index_name.as_bytes());
+    key.extend_from_slice(&local_id.to_be_bytes());
+    key
+}
+
+/// Owns the single, globally ordered update queue shared by every [`Index`]
+/// opened against the same store. Where each `Index` used to run its own
+/// worker thread against its own `{name}-updates` tree, updates across all
+/// indexes now serialize through one `pending_queue`, applied strictly in
+/// the order they were enqueued.
+///
+/// Generic over [`KvStore`] so the shared queue itself can run on a backend
+/// other than `sled`. `Index` still opens its own per-index trees
+/// (`MainIndex`, `WordsIndex`, etc.) directly against `sled::Db`, so for now
+/// only `UpdateStore<sled::Db>` is actually constructed; the type parameter
+/// exists so that boundary can move without reworking this module again.
+#[derive(Clone)]
+pub struct UpdateStore<S: KvStore = sled::Db> {
+    inner: Arc<UpdateStoreInner<S>>,
+}
+
+struct UpdateStoreInner<S: KvStore> {
+    store: S,
+    pending_queue: S::Tree,
+    /// Holds the store-wide last `global_id` (under `global-counter`) and
+    /// two things per index, both keyed off `index_name` (see
+    /// `counter_key`/`local_id_key`): this index's last assigned `local_id` — the
+    /// caller-facing `update_id`, numbered sequentially starting at 0 — and
+    /// the `local_id -> global_id` mapping needed to look a given `update_id`
+    /// back up in `pending_queue`/`updates_results`, which are themselves
+    /// keyed by the cross-index `global_id`.
+    next_update_id: S::Tree,
+    updates_results: S::Tree,
+    indexes: RwLock<HashMap<String, Index>>,
+    state: StateLock,
+    /// Serializes `drain_ready` calls made from `register` against the ones
+    /// the background worker makes on its own, so the two never both try to
+    /// apply the same head-of-queue update at once.
+    drain_lock: Mutex<()>,
+}
+
+impl UpdateStore<sled::Db> {
+    /// Opens the shared queue trees and spawns the single worker thread
+    /// that will apply every update enqueued against this store.
+    pub fn open(db: sled::Db) -> Result<UpdateStore<sled::Db>, Error> {
+        let pending_queue = db.open_tree("pending-updates")?;
+        let next_update_id = db.open_tree("pending-updates-ids")?;
+        let updates_results = db.open_tree("updates-results")?;
+
+        let inner = UpdateStoreInner {
+            store: db,
+            pending_queue,
+            next_update_id,
+            updates_results,
+            indexes: RwLock::new(HashMap::new()),
+            state: StateLock::new(),
+            drain_lock: Mutex::new(()),
+        };
+
+        let update_store = UpdateStore { inner: Arc::new(inner) };
+
+        let _handle = spawn_update_worker(update_store.clone());
+
+        Ok(update_store)
+    }
+}
+
+impl<S: KvStore> UpdateStore<S>
+where S::Error: Into<Error>
+{
+    /// Registers `index` under `name` so the worker thread can dispatch
+    /// queued updates to it by name.
+    ///
+    /// Drains whatever is already sitting at the head of `pending_queue` for
+    /// this index right away: the worker only wakes up on a write to
+    /// `pending_queue`, so on restart, updates enqueued for `name` before it
+    /// registers would otherwise sit there until the next unrelated enqueue
+    /// happened to nudge the worker awake.
+    pub(crate) fn register(&self, name: String, index: Index) {
+        self.inner.indexes.write().unwrap().insert(name, index);
+        self.drain_ready();
+    }
+
+    /// The lock gating the worker against anything needing a consistent
+    /// view of the store, e.g. `Index::snapshot`.
+    pub(crate) fn state_lock(&self) -> &StateLock {
+        &self.inner.state
+    }
+
+    /// Atomically bumps both `index_name`'s `local_id` counter and the
+    /// store-wide `global_id` counter, and pushes `raw_update` onto the
+    /// shared `pending_queue` keyed by `global_id` so it sorts after every
+    /// update enqueued before it, regardless of which index it targets.
+    ///
+    /// Returns the `local_id`, i.e. the `update_id` every other method on
+    /// this store expects callers to use.
+    pub(crate) fn enqueue(&self, index_name: &str, raw_update: Vec<u8>) -> Result<u64, Error> {
+        // sampled once, outside the transaction below, so a conflict retry
+        // doesn't shift it to whichever attempt happens to commit
+        let enqueued_at = Utc::now();
+
+        let pending_queue = &self.inner.pending_queue;
+        let next_update_id = &self.inner.next_update_id;
+        let counter_key = counter_key(index_name);
+
+        let local_id = self.inner.store.transaction(pending_queue, next_update_id, |pending_queue, next_update_id| {
+            // both ids are read-then-bumped atomically with the rest of the
+            // insert, so two concurrent `enqueue` calls can never land on the
+            // same `global_id`, nor on the same `local_id` for one index
+            let global_id = match next_update_id.get(b"global-counter")? {
+                Some(value) => u64::from_be_bytes(value[..].try_into().unwrap()) + 1,
+                None => 0,
+            };
+            let local_id = match next_update_id.get(&counter_key)? {
+                Some(value) => u64::from_be_bytes(value[..].try_into().unwrap()) + 1,
+                None => 0,
+            };
+
+            next_update_id.insert(b"global-counter", global_id.to_be_bytes().to_vec())?;
+            next_update_id.insert(&counter_key, local_id.to_be_bytes().to_vec())?;
+            next_update_id.insert(&local_id_key(index_name, local_id), global_id.to_be_bytes().to_vec())?;
+
+            let pending = PendingUpdateRef { local_id, enqueued_at, update: &raw_update };
+            let pending = bincode::serialize(&pending).unwrap();
+
+            let key = update_key(global_id, index_name);
+            pending_queue.insert(&key, pending)?;
+
+            Ok(local_id)
+        }).map_err(Into::into)?;
+
+        Ok(local_id)
+    }
+
+    /// Translates a caller-facing `update_id` (a `local_id`) back into the
+    /// `global_id` `pending_queue`/`updates_results` are actually keyed by.
+    /// `None` if `index_name` never enqueued that `local_id`.
+    fn resolve_global_id(&self, index_name: &str, local_id: u64) -> Result<Option<u64>, Error> {
+        match self.inner.next_update_id.get(&local_id_key(index_name, local_id)).map_err(Into::into)? {
+            Some(value) => Ok(Some(u64::from_be_bytes(value[..].try_into().unwrap()))),
+            None => Ok(None),
+        }
+    }
+
+    /// Reports whether `update_id` is still queued, has been processed, or
+    /// is unknown to this index.
+    pub(crate) fn status(&self, index_name: &str, update_id: u64) -> Result<UpdateStatus, Error> {
+        let global_id = match self.resolve_global_id(index_name, update_id)? {
+            Some(global_id) => global_id,
+            None => return Ok(UpdateStatus::Unknown),
+        };
+        let key = update_key(global_id, index_name);
+
+        if let Some(value) = self.inner.updates_results.get(&key).map_err(Into::into)? {
+            return Ok(UpdateStatus::Processed(bincode::deserialize(&value)?));
+        }
+
+        if let Some(value) = self.inner.pending_queue.get(&key).map_err(Into::into)? {
+            let pending: PendingUpdate = bincode::deserialize(&value)?;
+            let update_type = describe_update(&rmp_serde::from_read_ref(&pending.update).unwrap());
+
+            // compare on `global_id`, not `update_id` (a `local_id`): those
+            // restart at 0 per index, so two different indexes' update 0
+            // would otherwise be indistinguishable here
+            if self.inner.state.current() == UpdateState::Processing(global_id) {
+                return Ok(UpdateStatus::Processing { update_id, update_type, enqueued_at: pending.enqueued_at });
+            }
+
+            return Ok(UpdateStatus::Enqueued { update_id, update_type, enqueued_at: pending.enqueued_at });
+        }
+
+        Ok(UpdateStatus::Unknown)
+    }
+
+    /// Blocks until `update_id` has been processed for `index_name`, then
+    /// returns its result.
+    ///
+    /// Panics if `update_id` was never enqueued against `index_name` — every
+    /// caller gets `update_id` from this store's own `enqueue`, so that
+    /// would mean a caller mixed up ids across indexes.
+    pub(crate) fn wait_result(&self, index_name: &str, update_id: u64) -> Result<UpdateResult, Error> {
+        let global_id = self.resolve_global_id(index_name, update_id)?
+            .expect("wait_result called with an update_id that was never enqueued");
+        let key = update_key(global_id, index_name);
+        let subscription = self.inner.updates_results.watch_prefix(&key);
+
+        if let Some(value) = self.inner.updates_results.get(&key).map_err(Into::into)? {
+            return Ok(bincode::deserialize(&value)?);
+        }
+
+        // this subscription is used to block the thread until the update
+        // result is inserted in the tree
+        subscription.wait();
+
+        let value = self.inner.updates_results.get(&key).map_err(Into::into)?.unwrap();
+        Ok(bincode::deserialize(&value)?)
+    }
+
+    /// Iterates every update result recorded for `index_name`, in enqueue order.
+    pub(crate) fn results_for_index<'a>(
+        &'a self,
+        index_name: &'a str,
+    ) -> impl Iterator<Item = Result<UpdateResult, Error>> + 'a {
+        self.inner.updates_results.iter().filter_map(move |result| {
+            let (key, value) = result.ok()?;
+            let (_, name) = decode_update_key(&key);
+            if name != index_name {
+                return None;
+            }
+            Some(bincode::deserialize(&value).map_err(Error::from))
+        })
+    }
+
+    /// Copies `index_name`'s own slice of the shared update queue — its
+    /// still-pending updates and its recorded results — into fresh
+    /// `{index_name}-updates`/`{index_name}-updates-results` trees opened on
+    /// `target`, keyed by `local_id` so a snapshot restored elsewhere is
+    /// self-contained without depending on the rest of the store's indexes.
+    pub(crate) fn snapshot_index(&self, index_name: &str, target: &S) -> Result<(), Error> {
+        let pending = target.open_tree(&format!("{}-updates", index_name)).map_err(Into::into)?;
+        let results = target.open_tree(&format!("{}-updates-results", index_name)).map_err(Into::into)?;
+
+        for entry in self.inner.pending_queue.iter() {
+            let (key, value) = entry.map_err(Into::into)?;
+            let (_, name) = decode_update_key(&key);
+            if name != index_name {
+                continue;
+            }
+            let local_id = bincode::deserialize::<PendingUpdate>(&value).unwrap().local_id;
+            pending.insert(&local_id.to_be_bytes(), value).map_err(Into::into)?;
+        }
+
+        for entry in self.inner.updates_results.iter() {
+            let (key, value) = entry.map_err(Into::into)?;
+            let (_, name) = decode_update_key(&key);
+            if name != index_name {
+                continue;
+            }
+            let update_result: UpdateResult = bincode::deserialize(&value)?;
+            results.insert(&update_result.update_id.to_be_bytes(), value).map_err(Into::into)?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies whatever is sitting at the front of `pending_queue`, for as
+    /// long as each next update's target index is registered. Called both
+    /// by `register` (to clear a backlog the worker stalled on) and by the
+    /// worker itself, serialized through `drain_lock` so the two never race
+    /// to apply the same update.
+    fn drain_ready(&self) {
+        let _guard = self.inner.drain_lock.lock().unwrap();
+        while apply_next_ready(self) {}
+    }
+}
+
+/// Applies the update at the front of `pending_queue`, if any, and if the
+/// index it targets is registered. Returns whether an update was applied,
+/// so `drain_ready` can keep going until the queue runs dry or stalls on an
+/// unregistered index.
+fn apply_next_ready<S: KvStore>(update_store: &UpdateStore<S>) -> bool {
+    let (key, value) = match update_store.inner.pending_queue.iter().next() {
+        Some(result) => result.unwrap(),
+        None => return false,
+    };
+    let (global_id, index_name) = decode_update_key(&key);
+
+    let index = match update_store.inner.indexes.read().unwrap().get(index_name) {
+        Some(index) => index.clone(),
+        // the index this update targets has not been registered (yet, or
+        // anymore); leave it queued rather than drop it
+        None => return false,
+    };
+
+    let pending: PendingUpdate = bincode::deserialize(&value).unwrap();
+
+    // reported by `UpdateStore::status` for as long as this update is
+    // being applied; `global_id` since `status` needs to tell updates of
+    // different indexes apart and `local_id`s restart at 0 per index
+    let _guard = update_store.inner.state.processing(global_id);
+
+    // `apply_*` mutates the index's own trees, swaps its `Cache`, and fires
+    // `update_callback` — none of that belongs inside `store.transaction`:
+    // sled re-runs a conflicting transaction's closure from scratch, which
+    // would apply the update (and fire the callback) more than once. Only
+    // the mechanical dequeue + result-write below need to be atomic.
+    let (update_type, result, duration) = match rmp_serde::from_read_ref(&pending.update).unwrap() {
+        UpdateOwned::DocumentsAddition(documents) => {
+            let update_type = UpdateType::DocumentsAddition { number: documents.len() };
+            let ranked_map = index.cache.load().ranked_map.clone();
+            let start = Instant::now();
+            let result = apply_documents_addition(&index, ranked_map, documents);
+            (update_type, result, start.elapsed())
+        },
+        UpdateOwned::DocumentsPartialAddition(documents) => {
+            let update_type = UpdateType::DocumentsPartialAddition { number: documents.len() };
+            let ranked_map = index.cache.load().ranked_map.clone();
+            let start = Instant::now();
+            let result = apply_documents_partial_addition(&index, ranked_map, documents);
+            (update_type, result, start.elapsed())
+        },
+        UpdateOwned::DocumentsDeletion(documents) => {
+            let update_type = UpdateType::DocumentsDeletion { number: documents.len() };
+            let ranked_map = index.cache.load().ranked_map.clone();
+            let start = Instant::now();
+            let result = apply_documents_deletion(&index, ranked_map, documents);
+            (update_type, result, start.elapsed())
+        },
+        UpdateOwned::SynonymsAddition(synonyms) => {
+            let update_type = UpdateType::SynonymsAddition { number: synonyms.len() };
+            let start = Instant::now();
+            let result = apply_synonyms_addition(&index, synonyms);
+            (update_type, result, start.elapsed())
+        },
+        UpdateOwned::SynonymsDeletion(synonyms) => {
+            let update_type = UpdateType::SynonymsDeletion { number: synonyms.len() };
+            let start = Instant::now();
+            let result = apply_synonyms_deletion(&index, synonyms);
+            (update_type, result, start.elapsed())
+        },
+        UpdateOwned::ClearAll => {
+            let update_type = UpdateType::ClearAll;
+            let start = Instant::now();
+            let result = apply_clear_all(&index);
+            (update_type, result, start.elapsed())
+        },
+        UpdateOwned::SchemaUpdate(schema) => {
+            let update_type = UpdateType::SchemaUpdate;
+            let start = Instant::now();
+            let result = apply_schema_update(&index, schema);
+            (update_type, result, start.elapsed())
+        },
+        UpdateOwned::StopWordsAddition(stop_words) => {
+            let update_type = UpdateType::StopWordsAddition { number: stop_words.len() };
+            let start = Instant::now();
+            let result = apply_stop_words_addition(&index, stop_words);
+            (update_type, result, start.elapsed())
+        },
+        UpdateOwned::StopWordsDeletion(stop_words) => {
+            let update_type = UpdateType::StopWordsDeletion { number: stop_words.len() };
+            let start = Instant::now();
+            let result = apply_stop_words_deletion(&index, stop_words);
+            (update_type, result, start.elapsed())
+        },
+        UpdateOwned::CustomsUpdate(customs) => {
+            let update_type = UpdateType::CustomsUpdate;
+            let start = Instant::now();
+            let result = apply_customs_update(&index, customs);
+            (update_type, result, start.elapsed())
+        },
+    };
+
+    let detailed_duration = DetailedDuration { main: duration };
+    let update_result = UpdateResult {
+        update_id: pending.local_id,
+        update_type,
+        result: result.map_err(|e| e.to_string()),
+        detailed_duration,
+        enqueued_at: pending.enqueued_at,
+        processed_at: Utc::now(),
+    };
+
+    if let Some(callback) = &*index.update_callback.load() {
+        (callback)(update_result.clone());
+    }
+
+    let pending_queue = &update_store.inner.pending_queue;
+    let updates_results = &update_store.inner.updates_results;
+    let value = bincode::serialize(&update_result).unwrap();
+
+    update_store.inner.store.transaction(pending_queue, updates_results, |pending_queue, updates_results| {
+        pending_queue.remove(&key)?;
+        updates_results.insert(&key, value.clone())
+    }).unwrap();
+
+    true
+}
+
+fn spawn_update_worker(update_store: UpdateStore<sled::Db>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            // this subscription is just used to block the loop until a new
+            // update is inserted, so it must be taken before draining,
+            // otherwise a write landing between the drain and the subscribe
+            // would be missed until some later, unrelated write woke us up
+            let subscription = KvTree::watch_prefix(&update_store.inner.pending_queue, &[]);
+
+            update_store.drain_ready();
+
+            subscription.wait();
+        }
+    })
+}